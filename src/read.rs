@@ -1,7 +1,138 @@
-use futures_util::io::{self as fio, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::future::poll_fn;
+use futures_util::io::{self as fio, AsyncRead, AsyncReadExt};
 
 use crate::*;
 
+/// Wraps a reader and tracks how many bytes have been pulled through it, so
+/// the parser can compute track boundaries and error offsets without ever
+/// calling `seek`. This is what lets `read` accept non-seekable transports
+/// like pipes and sockets.
+struct CountingReader<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R> CountingReader<R> {
+    #[inline]
+    fn new(inner: R) -> Self {
+        Self { inner, pos: 0 }
+    }
+    #[inline]
+    fn position(&self) -> u64 {
+        self.pos
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<fio::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = &result {
+            this.pos += *n as u64;
+        }
+        result
+    }
+}
+
+/// Default capacity for the internal buffer used by [`read`]. Large enough
+/// to batch most per-byte VLQ/status reads into a handful of underlying
+/// `poll_read` calls per track, without holding onto much memory.
+pub const DEFAULT_BUFFER_CAPACITY: usize = 8 * 1024;
+
+/// Smallest buffer [`BufferedReader::with_capacity`] will allocate. A buffer
+/// of 0 could never hold the single byte `peek_byte` needs, so capacity is
+/// floored here rather than letting every event peek fail with a spurious
+/// `UnexpectedEof`.
+const MIN_BUFFER_CAPACITY: usize = 1;
+
+/// A `BufReader`-style wrapper that serves small reads (VLQs, status bytes,
+/// fixed-size header fields) out of an internal buffer, refilling it with a
+/// single `poll_read` once it's drained. Requests at least as large as the
+/// buffer bypass it entirely and read straight into the caller's slice, so a
+/// large sysex or meta event payload is never copied twice.
+struct BufferedReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,
+    filled: usize,
+}
+
+impl<R> BufferedReader<R> {
+    /// `capacity` is floored to [`MIN_BUFFER_CAPACITY`].
+    fn with_capacity(capacity: usize, inner: R) -> Self {
+        Self {
+            inner,
+            buf: vec![0u8; capacity.max(MIN_BUFFER_CAPACITY)],
+            pos: 0,
+            filled: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for BufferedReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<fio::Result<usize>> {
+        let this = self.get_mut();
+        if this.pos < this.filled {
+            let n = out.len().min(this.filled - this.pos);
+            out[..n].copy_from_slice(&this.buf[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(n));
+        }
+        if out.len() >= this.buf.len() {
+            return Pin::new(&mut this.inner).poll_read(cx, out);
+        }
+        match Pin::new(&mut this.inner).poll_read(cx, &mut this.buf[..]) {
+            Poll::Ready(Ok(filled)) => {
+                let n = out.len().min(filled);
+                out[..n].copy_from_slice(&this.buf[..n]);
+                this.pos = n;
+                this.filled = filled;
+                Poll::Ready(Ok(n))
+            }
+            other => other,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> BufferedReader<R> {
+    /// Returns the next byte without consuming it, refilling the internal
+    /// buffer first if it's drained. A later `read_u8`/`read_exact` will see
+    /// this same byte.
+    async fn peek_byte(&mut self) -> fio::Result<u8> {
+        if self.pos == self.filled {
+            let filled = poll_fn(|cx| Pin::new(&mut self.inner).poll_read(cx, &mut self.buf[..]))
+                .await?;
+            if filled == 0 {
+                return Err(fio::Error::new(
+                    fio::ErrorKind::UnexpectedEof,
+                    "unexpected end of file",
+                ));
+            }
+            self.pos = 0;
+            self.filled = filled;
+        }
+        Ok(self.buf[self.pos])
+    }
+}
+
+impl<R: AsyncRead + Unpin> CountingReader<BufferedReader<R>> {
+    #[inline]
+    async fn peek_byte(&mut self) -> fio::Result<u8> {
+        self.inner.peek_byte().await
+    }
+}
+
 pub trait ReadError {
     fn io_error(error: fio::Error) -> Self;
 }
@@ -13,6 +144,18 @@ impl ReadError for fio::Error {
     }
 }
 
+/// What [`ReadHandler::peek_status`] tells the parser to do with the event
+/// that follows.
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub enum PeekAction {
+    /// Decode the event as usual and dispatch it to its `*_event` method.
+    Continue,
+    /// Discard the event's payload without allocating it or calling the
+    /// corresponding `*_event` method. The stream is still advanced past the
+    /// event so parsing of the track can continue.
+    Skip,
+}
+
 #[allow(unused)]
 pub trait ReadHandler {
     type Error: ReadError;
@@ -27,6 +170,14 @@ pub trait ReadHandler {
     fn track(&mut self) -> Result<(), Self::Error> {
         Ok(())
     }
+    /// Called with the next status byte before the event it starts is
+    /// decoded. Returning [`PeekAction::Skip`] tells the parser to discard
+    /// the event's payload without allocating it or calling the matching
+    /// `*_event` method; returning [`PeekAction::Continue`] (the default)
+    /// decodes and dispatches it as usual.
+    fn peek_status(&mut self, status: u8) -> Result<PeekAction, Self::Error> {
+        Ok(PeekAction::Continue)
+    }
     fn midi_event(&mut self, delta: u32, data: &[u8]) -> Result<(), Self::Error> {
         Ok(())
     }
@@ -59,7 +210,7 @@ async fn read_u32_be<R: AsyncRead + Unpin>(reader: &mut R) -> fio::Result<u32> {
     reader.read_exact(&mut buf).await?;
     Ok(u32::from_be_bytes(buf))
 }
-async fn read_vlq<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R) -> fio::Result<u32> {
+async fn read_vlq<R: AsyncRead + Unpin>(reader: &mut CountingReader<R>) -> fio::Result<u32> {
     let mut value = 0u32;
     let mut count = 0;
     loop {
@@ -70,16 +221,21 @@ async fn read_vlq<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R) -> fio::Resu
         }
         count += 1;
         if count >= 4 {
-            let pos = reader.stream_position().await?;
             return Err(fio::Error::new(
                 fio::ErrorKind::InvalidData,
-                format!("VLQ too long (byte {:#04X} at {:#x})", c, pos),
+                format!(
+                    "VLQ too long (byte {:#04X} at {:#x})",
+                    c,
+                    reader.position()
+                ),
             ));
         }
     }
     Ok(value)
 }
-async fn read_vlq_event<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R) -> fio::Result<Vec<u8>> {
+async fn read_vlq_event<R: AsyncRead + Unpin>(
+    reader: &mut CountingReader<R>,
+) -> fio::Result<Vec<u8>> {
     let length = read_vlq(reader).await?;
     let mut data = vec![0u8; length as usize];
     if length > 0 {
@@ -87,6 +243,25 @@ async fn read_vlq_event<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R) -> fio
     }
     Ok(data)
 }
+/// Like [`read_vlq_event`], but discards the payload instead of allocating
+/// it, returning only its last byte (if any) so callers that need it for
+/// framing decisions, e.g. sysex continuation, don't have to materialize the
+/// whole payload to get it.
+async fn skip_vlq_event<R: AsyncRead + Unpin>(
+    reader: &mut CountingReader<R>,
+) -> fio::Result<Option<u8>> {
+    let mut remaining = read_vlq(reader).await? as usize;
+    let mut last = None;
+    let mut buf = [0u8; 64];
+    while remaining > 0 {
+        let chunk = remaining.min(buf.len());
+        reader.read_exact(&mut buf[..chunk]).await?;
+        last = Some(buf[chunk - 1]);
+        remaining -= chunk;
+    }
+    Ok(last)
+}
+
 #[inline]
 async fn read_chunk_type<R: AsyncRead + Unpin>(reader: &mut R) -> fio::Result<[u8; 4]> {
     let mut buf = [0u8; 4];
@@ -94,6 +269,19 @@ async fn read_chunk_type<R: AsyncRead + Unpin>(reader: &mut R) -> fio::Result<[u
     Ok(buf)
 }
 
+/// Sysex payloads may end with an `0xF7` terminator, which is a framing
+/// byte rather than u7 data; the writer excludes it from its own validation
+/// (`skip(1).take(len - 2)` over `[status, ..data, 0xF7]`), so strip it here
+/// before checking the rest of the payload the same way.
+#[inline]
+fn strip_sysex_terminator(data: &[u8]) -> &[u8] {
+    if data.last() == Some(&0xF7) {
+        &data[..data.len() - 1]
+    } else {
+        data
+    }
+}
+
 #[inline]
 fn validate_u7(offset: u64, data: &[u8]) -> fio::Result<()> {
     for (i, b) in data.iter().enumerate() {
@@ -110,8 +298,25 @@ fn validate_u7(offset: u64, data: &[u8]) -> fio::Result<()> {
 pub async fn read<H, R>(handler: &mut H, reader: &mut R) -> Result<(), H::Error>
 where
     H: ReadHandler,
-    R: AsyncRead + AsyncSeek + Unpin,
+    R: AsyncRead + Unpin,
+{
+    read_with_capacity(handler, reader, DEFAULT_BUFFER_CAPACITY).await
+}
+
+/// Same as [`read`], but with an explicit capacity for the internal read
+/// buffer instead of [`DEFAULT_BUFFER_CAPACITY`]. `capacity` is floored to a
+/// minimum of 1 byte (a buffer too small to hold a single byte can't serve a
+/// peek).
+pub async fn read_with_capacity<H, R>(
+    handler: &mut H,
+    reader: &mut R,
+    capacity: usize,
+) -> Result<(), H::Error>
+where
+    H: ReadHandler,
+    R: AsyncRead + Unpin,
 {
+    let reader = &mut CountingReader::new(BufferedReader::with_capacity(capacity, reader));
     let magic = read_chunk_type(reader).await.map_err(H::Error::io_error)?;
     let header_len = read_u32_be(reader).await.map_err(H::Error::io_error)?;
     let format = read_u16_be(reader).await.map_err(H::Error::io_error)?;
@@ -168,13 +373,12 @@ where
         }
         handler.track()?;
 
-        let track_end =
-            reader.stream_position().await.map_err(H::Error::io_error)? + (track_len as u64);
+        let track_end = reader.position() + (track_len as u64);
         let mut sysex_continuation = false;
         let mut last_status = 0;
 
         loop {
-            let current_pos = reader.stream_position().await.map_err(H::Error::io_error)?;
+            let current_pos = reader.position();
             if current_pos == track_end {
                 break;
             }
@@ -188,83 +392,141 @@ where
                 )));
             }
             let delta = read_vlq(reader).await.map_err(H::Error::io_error)?;
-            let status = read_u8(reader).await.map_err(H::Error::io_error)?;
-            let running_status = if (0x80..0xF0).contains(&status) {
-                last_status = status;
-                false
-            } else {
-                true
-            };
+            let peeked = reader.peek_byte().await.map_err(H::Error::io_error)?;
+
             if sysex_continuation {
-                if status != 0xF7 {
-                    let pos = reader.stream_position().await.map_err(H::Error::io_error)?;
+                if peeked != 0xF7 {
+                    read_u8(reader).await.map_err(H::Error::io_error)?;
+                    let pos = reader.position();
                     return Err(H::Error::io_error(fio::Error::new(
                         fio::ErrorKind::InvalidData,
                         format!(
                             "expected sysex continuation 0xF7, got {:#04X} in track {} at {:#x}",
-                            status, track_index, pos,
+                            peeked, track_index, pos,
                         ),
                     )));
                 }
+                let skip = handler.peek_status(peeked)? == PeekAction::Skip;
+                read_u8(reader).await.map_err(H::Error::io_error)?;
                 last_status = 0;
 
-                let data = read_vlq_event(reader).await.map_err(H::Error::io_error)?;
-                if data.last() == Some(&0xF7) {
-                    sysex_continuation = false;
-                };
-                let pos = reader.stream_position().await.map_err(H::Error::io_error)?;
-                validate_u7(pos, &data).map_err(H::Error::io_error)?;
-                handler.sysex_event(delta, data)?;
-            } else if status == 0xF0 {
+                if skip {
+                    let last_byte = skip_vlq_event(reader).await.map_err(H::Error::io_error)?;
+                    if last_byte == Some(0xF7) {
+                        sysex_continuation = false;
+                    }
+                } else {
+                    let data = read_vlq_event(reader).await.map_err(H::Error::io_error)?;
+                    if data.last() == Some(&0xF7) {
+                        sysex_continuation = false;
+                    };
+                    // `reader.position()` is the stream position right after
+                    // the payload; `pos` is where the payload (and thus
+                    // `checked`, a prefix of it) started, `data.len()` bytes
+                    // earlier.
+                    let pos = reader.position() - data.len() as u64;
+                    let checked = strip_sysex_terminator(&data);
+                    validate_u7(pos, checked).map_err(H::Error::io_error)?;
+                    handler.sysex_event(delta, data)?;
+                }
+            } else if peeked == 0xF0 {
+                let skip = handler.peek_status(peeked)? == PeekAction::Skip;
+                read_u8(reader).await.map_err(H::Error::io_error)?;
                 last_status = 0;
 
-                let data = read_vlq_event(reader).await.map_err(H::Error::io_error)?;
-                if data.last() != Some(&0xF7) {
-                    sysex_continuation = true;
+                if skip {
+                    let last_byte = skip_vlq_event(reader).await.map_err(H::Error::io_error)?;
+                    if last_byte != Some(0xF7) {
+                        sysex_continuation = true;
+                    }
+                } else {
+                    let data = read_vlq_event(reader).await.map_err(H::Error::io_error)?;
+                    if data.last() != Some(&0xF7) {
+                        sysex_continuation = true;
+                    }
+                    // `reader.position()` is the stream position right after
+                    // the payload; `pos` is where the payload (and thus
+                    // `checked`, a prefix of it) started, `data.len()` bytes
+                    // earlier.
+                    let pos = reader.position() - data.len() as u64;
+                    let checked = strip_sysex_terminator(&data);
+                    validate_u7(pos, checked).map_err(H::Error::io_error)?;
+                    handler.sysex_event(delta, data)?;
                 }
-                let pos = reader.stream_position().await.map_err(H::Error::io_error)?;
-                validate_u7(pos, &data).map_err(H::Error::io_error)?;
-                handler.sysex_event(delta, data)?;
-            } else if status == 0xF7 {
+            } else if peeked == 0xF7 {
+                let skip = handler.peek_status(peeked)? == PeekAction::Skip;
+                read_u8(reader).await.map_err(H::Error::io_error)?;
                 last_status = 0;
-                let data = read_vlq_event(reader).await.map_err(H::Error::io_error)?;
-                handler.escaped_event(delta, data)?;
-            } else if status == 0xFF {
+                if skip {
+                    skip_vlq_event(reader).await.map_err(H::Error::io_error)?;
+                } else {
+                    let data = read_vlq_event(reader).await.map_err(H::Error::io_error)?;
+                    handler.escaped_event(delta, data)?;
+                }
+            } else if peeked == 0xFF {
+                let skip = handler.peek_status(peeked)? == PeekAction::Skip;
+                read_u8(reader).await.map_err(H::Error::io_error)?;
                 last_status = 0;
                 let meta_type = read_u8(reader).await.map_err(H::Error::io_error)?;
-                validate_u7(current_pos + 1, &[meta_type]).map_err(H::Error::io_error)?;
-                let data = read_vlq_event(reader).await.map_err(H::Error::io_error)?;
-                handler.meta_event(delta, meta_type, data)?;
-            } else if last_status != 0 {
-                let length = match last_status & 0xF0 {
+                if skip {
+                    skip_vlq_event(reader).await.map_err(H::Error::io_error)?;
+                } else {
+                    validate_u7(current_pos + 1, &[meta_type]).map_err(H::Error::io_error)?;
+                    let data = read_vlq_event(reader).await.map_err(H::Error::io_error)?;
+                    handler.meta_event(delta, meta_type, data)?;
+                }
+            } else if (0x80..0xF0).contains(&peeked) {
+                let skip = handler.peek_status(peeked)? == PeekAction::Skip;
+                read_u8(reader).await.map_err(H::Error::io_error)?;
+                last_status = peeked;
+                let length = match peeked & 0xF0 {
                     0xC0 | 0xD0 => 2,
                     _ => 3,
                 };
                 let mut data = [0u8; 3];
-                data[0] = last_status;
-                let offset = if running_status {
-                    data[1] = status;
-                    1
-                } else {
-                    0
-                };
-                let range = 1 + offset..length;
-                if range.end - range.start > 0 {
+                data[0] = peeked;
+                if length > 1 {
                     reader
-                        .read_exact(&mut data[range])
+                        .read_exact(&mut data[1..length])
                         .await
                         .map_err(H::Error::io_error)?;
                 }
-                validate_u7(current_pos + (1 - offset as u64), &data[1..length])
+                if !skip {
+                    validate_u7(reader.position() - (length as u64 - 1), &data[1..length])
+                        .map_err(H::Error::io_error)?;
+                    handler.midi_event(delta, &data[0..length])?;
+                }
+            } else if last_status != 0 {
+                // `peeked` is a data byte reused under running status, not a
+                // status byte; peek_status must see the status it's actually
+                // reporting on, so resolve it to `last_status` and leave
+                // `peeked` in the stream to be read as the first data byte
+                // below.
+                let skip = handler.peek_status(last_status)? == PeekAction::Skip;
+                let length = match last_status & 0xF0 {
+                    0xC0 | 0xD0 => 2,
+                    _ => 3,
+                };
+                let mut data = [0u8; 3];
+                data[0] = last_status;
+                reader
+                    .read_exact(&mut data[1..length])
+                    .await
                     .map_err(H::Error::io_error)?;
-                handler.midi_event(delta, &data[0..length])?;
+                if !skip {
+                    validate_u7(reader.position() - (length as u64 - 1), &data[1..length])
+                        .map_err(H::Error::io_error)?;
+                    handler.midi_event(delta, &data[0..length])?;
+                }
             } else {
-                let pos = reader.stream_position().await.map_err(H::Error::io_error)?;
+                handler.peek_status(peeked)?;
+                read_u8(reader).await.map_err(H::Error::io_error)?;
+                let pos = reader.position();
                 return Err(H::Error::io_error(fio::Error::new(
                     fio::ErrorKind::InvalidData,
                     format!(
                         "expected valid status byte, got {:#04X} in track {} at {:#x}",
-                        status, track_index, pos,
+                        peeked, track_index, pos,
                     ),
                 )));
             }
@@ -272,3 +534,124 @@ where
     }
     Ok(())
 }
+
+/// Number of bytes in an `MThd` chunk, including the `MThd` magic and the
+/// 4-byte chunk length that precedes `format`/`num_tracks`/`division`.
+pub const HEADER_LEN: usize = 14;
+
+/// The parsed contents of an `MThd` chunk, as returned by [`probe`].
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Header {
+    pub format: Format,
+    pub num_tracks: u16,
+    pub division: Division,
+}
+
+/// The result of a successful [`probe`]: the parsed header, plus the raw
+/// bytes that were consumed reading it. Feeding `consumed` back in ahead of
+/// the rest of the reader (e.g. via `(&consumed[..]).chain(reader)`) lets a
+/// subsequent [`read`] pick up at the first `MTrk` chunk without the header
+/// being read twice.
+#[derive(Clone, Debug)]
+pub struct Probed {
+    pub header: Header,
+    pub consumed: [u8; HEADER_LEN],
+}
+
+/// Why [`probe`] couldn't classify a reader as MIDI data.
+#[derive(Debug)]
+pub enum ProbeError {
+    /// The first 4 bytes weren't `MThd`, so this isn't a MIDI file at all.
+    NotMidi([u8; 4]),
+    /// The input ended before the header was complete (including before the
+    /// 4-byte magic could be read), or the `MThd` magic matched but the
+    /// header's contents didn't make sense (bad length, bad format, ...).
+    InvalidHeader(fio::Error),
+}
+
+impl std::fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeError::NotMidi(magic) => write!(f, "not a MIDI file: found {:?}", magic),
+            ProbeError::InvalidHeader(error) => write!(f, "invalid MIDI header: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ProbeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProbeError::NotMidi(_) => None,
+            ProbeError::InvalidHeader(error) => Some(error),
+        }
+    }
+}
+
+/// Peeks the 14-byte `MThd` header off `reader` and classifies it, without
+/// reading any further into the file. Unlike [`read`], this never consumes
+/// more than [`HEADER_LEN`] bytes, so it works the same on a file, a pipe, or
+/// a socket, and lets a caller distinguish "not MIDI" from "corrupt/short
+/// transfer" before committing to a full parse.
+pub async fn probe<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Probed, ProbeError> {
+    let mut buf = [0u8; HEADER_LEN];
+    reader
+        .read_exact(&mut buf[0..4])
+        .await
+        .map_err(ProbeError::InvalidHeader)?;
+
+    let mut magic = [0u8; 4];
+    magic.copy_from_slice(&buf[0..4]);
+    if &magic != b"MThd" {
+        return Err(ProbeError::NotMidi(magic));
+    }
+    reader
+        .read_exact(&mut buf[4..])
+        .await
+        .map_err(ProbeError::InvalidHeader)?;
+    let header_len = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+    if header_len != 6 {
+        return Err(ProbeError::InvalidHeader(fio::Error::new(
+            fio::ErrorKind::InvalidData,
+            format!("invalid MIDI header length: {}", header_len),
+        )));
+    }
+    let format = u16::from_be_bytes(buf[8..10].try_into().unwrap());
+    let num_tracks = u16::from_be_bytes(buf[10..12].try_into().unwrap());
+    let division = u16::from_be_bytes(buf[12..14].try_into().unwrap());
+
+    let format = match format {
+        0 => Format::Single,
+        1 => Format::Multiple,
+        2 => Format::Sequential,
+        _ => {
+            return Err(ProbeError::InvalidHeader(fio::Error::new(
+                fio::ErrorKind::InvalidData,
+                format!("invalid MIDI format: {}", format),
+            )))
+        }
+    };
+    let division = if (division as i16) < 0 {
+        let fps = -(division as i16 >> 8) as u8;
+        if fps != 24 && fps != 25 && fps != 29 && fps != 30 {
+            return Err(ProbeError::InvalidHeader(fio::Error::new(
+                fio::ErrorKind::InvalidData,
+                format!("invalid SMPTE format: {:#04X}", -(fps as i8)),
+            )));
+        }
+        Division::SMPTE {
+            fps,
+            tpf: division as u8,
+        }
+    } else {
+        Division::PPQN(division)
+    };
+
+    Ok(Probed {
+        header: Header {
+            format,
+            num_tracks,
+            division,
+        },
+        consumed: buf,
+    })
+}
@@ -1,7 +1,59 @@
+use std::io::IoSlice;
+
 use futures_util::io::{self as fio, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 
 use crate::*;
 
+#[inline]
+fn encode_vlq(v: u32, buf: &mut [u8]) -> usize {
+    debug_assert!(v <= 0xfffffff);
+
+    #[inline]
+    const fn b(v: u32, byte: u32) -> u8 {
+        let byte = byte * 7;
+        let last = if byte > 0 { 0x80 } else { 0 };
+        ((v & (0x7f << byte)) >> byte) as u8 | last
+    }
+
+    let bytes: &[u8] = if v > 0x1fffff {
+        &[b(v, 3), b(v, 2), b(v, 1), b(v, 0)]
+    } else if v > 0x3fff {
+        &[b(v, 2), b(v, 1), b(v, 0)]
+    } else if v > 0x7f {
+        &[b(v, 1), b(v, 0)]
+    } else {
+        &[b(v, 0)]
+    };
+    buf[..bytes.len()].copy_from_slice(bytes);
+    bytes.len()
+}
+
+/// Writes a small header buffer and a caller-provided payload with a single
+/// `write_vectored` call, avoiding a copy of `data` into `prefix`. Falls back
+/// to plain `write_all` calls for whatever the vectored write didn't cover,
+/// which also handles writers that don't implement vectored I/O at all (the
+/// default `poll_write_vectored` just writes the first non-empty slice).
+async fn write_vectored<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    prefix: &[u8],
+    data: &[u8],
+) -> fio::Result<()> {
+    if data.is_empty() {
+        return writer.write_all(prefix).await;
+    }
+    let bufs = [IoSlice::new(prefix), IoSlice::new(data)];
+    let total = prefix.len() + data.len();
+    let n = writer.write_vectored(&bufs).await?;
+    if n == total {
+        Ok(())
+    } else if n <= prefix.len() {
+        writer.write_all(&prefix[n..]).await?;
+        writer.write_all(data).await
+    } else {
+        writer.write_all(&data[n - prefix.len()..]).await
+    }
+}
+
 pub async fn write<W: AsyncWrite + AsyncSeek + Unpin>(
     writer: &mut W,
     format: Format,
@@ -68,25 +120,9 @@ pub struct TrackWriter<'t, 'w, W: AsyncWrite + AsyncSeek + Unpin>(&'t mut Writer
 
 impl<'t, 'w, W: AsyncWrite + AsyncSeek + Unpin> TrackWriter<'t, 'w, W> {
     pub async fn vlq(&mut self, v: u32) -> fio::Result<()> {
-        debug_assert!(v <= 0xfffffff);
-
-        #[inline]
-        const fn b(v: u32, byte: u32) -> u8 {
-            let byte = byte * 7;
-            let last = if byte > 0 { 0x80 } else { 0 };
-            ((v & (0x7f << byte)) >> byte) as u8 | last
-        }
-
-        let w = &mut self.0.writer;
-        if v > 0x1fffff {
-            w.write_all(&[b(v, 3), b(v, 2), b(v, 1), b(v, 0)]).await
-        } else if v > 0x3fff {
-            w.write_all(&[b(v, 2), b(v, 1), b(v, 0)]).await
-        } else if v > 0x7f {
-            w.write_all(&[b(v, 1), b(v, 0)]).await
-        } else {
-            w.write_all(&[b(v, 0)]).await
-        }
+        let mut buf = [0u8; 4];
+        let len = encode_vlq(v, &mut buf);
+        self.0.writer.write_all(&buf[..len]).await
     }
     pub async fn raw_event(&mut self, delta: u32, data: &[u8]) -> fio::Result<()> {
         self.vlq(delta).await?;
@@ -101,32 +137,36 @@ impl<'t, 'w, W: AsyncWrite + AsyncSeek + Unpin> TrackWriter<'t, 'w, W> {
             data.len() == 3
         });
         debug_assert!(data.iter().skip(1).all(|b| *b < 0x80));
-        if data[0] == self.0.last_status {
-            self.raw_event(delta, &data[1..data.len()]).await?;
-        } else {
-            self.raw_event(delta, data).await?;
+        let mut prefix = [0u8; 5];
+        let mut len = encode_vlq(delta, &mut prefix);
+        if data[0] != self.0.last_status {
+            prefix[len] = data[0];
+            len += 1;
             self.0.last_status = data[0];
         }
-        Ok(())
+        write_vectored(self.0.writer, &prefix[..len], &data[1..]).await
     }
     pub async fn meta_event(&mut self, delta: u32, id: u8, data: &[u8]) -> fio::Result<()> {
         debug_assert!(!self.0.sysex_continuation);
         debug_assert!(id < 0x80);
         self.0.last_status = 0;
-        self.vlq(delta).await?;
-        self.0.writer.write_all(&[0xFFu8, id]).await?;
-        self.vlq(data.len().try_into().unwrap()).await?;
-        self.0.writer.write_all(data).await?;
-        Ok(())
+        let mut prefix = [0u8; 11];
+        let mut len = encode_vlq(delta, &mut prefix);
+        prefix[len] = 0xFF;
+        prefix[len + 1] = id;
+        len += 2;
+        len += encode_vlq(data.len().try_into().unwrap(), &mut prefix[len..]);
+        write_vectored(self.0.writer, &prefix[..len], data).await
     }
     pub async fn escaped_event(&mut self, delta: u32, data: &[u8]) -> fio::Result<()> {
         debug_assert!(!self.0.sysex_continuation);
         self.0.last_status = 0;
-        self.vlq(delta).await?;
-        self.0.writer.write_all(&[0xF7u8]).await?;
-        self.vlq(data.len().try_into().unwrap()).await?;
-        self.0.writer.write_all(data).await?;
-        Ok(())
+        let mut prefix = [0u8; 10];
+        let mut len = encode_vlq(delta, &mut prefix);
+        prefix[len] = 0xF7;
+        len += 1;
+        len += encode_vlq(data.len().try_into().unwrap(), &mut prefix[len..]);
+        write_vectored(self.0.writer, &prefix[..len], data).await
     }
     pub async fn sysex_event(&mut self, delta: u32, data: &[u8]) -> fio::Result<()> {
         let status = data[0];
@@ -144,11 +184,12 @@ impl<'t, 'w, W: AsyncWrite + AsyncSeek + Unpin> TrackWriter<'t, 'w, W> {
             debug_assert!(data.iter().skip(1).all(|b| *b < 0x80));
         }
         self.0.last_status = 0;
-        self.vlq(delta).await?;
-        self.0.writer.write_all(&[status]).await?;
-        self.vlq((data.len() - 1).try_into().unwrap()).await?;
-        self.0.writer.write_all(&data[1..data.len()]).await?;
-        Ok(())
+        let mut prefix = [0u8; 9];
+        let mut len = encode_vlq(delta, &mut prefix);
+        prefix[len] = status;
+        len += 1;
+        len += encode_vlq((data.len() - 1).try_into().unwrap(), &mut prefix[len..]);
+        write_vectored(self.0.writer, &prefix[..len], &data[1..]).await
     }
     pub async fn finish(self) -> fio::Result<()> {
         let track_len =
@@ -168,3 +209,130 @@ impl<'t, 'w, W: AsyncWrite + AsyncSeek + Unpin> TrackWriter<'t, 'w, W> {
         Ok(())
     }
 }
+
+/// Like [`write`], but for targets that can't be seeked (sockets, pipes).
+/// The number of tracks has to be known up front so the header can be
+/// written immediately, and each track's body is buffered in memory so its
+/// length can be prefixed before any of its bytes hit the writer.
+pub async fn write_streaming<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    format: Format,
+    num_tracks: u16,
+    division: Division,
+) -> fio::Result<StreamingWriter<'_, W>> {
+    writer.write_all(b"MThd").await?;
+    writer.write_all(&6u32.to_be_bytes()).await?;
+    writer.write_all(&(format as u16).to_be_bytes()).await?;
+    writer.write_all(&num_tracks.to_be_bytes()).await?;
+    let division = match division {
+        Division::PPQN(d) => d,
+        Division::SMPTE { fps, tpf } => ((-(fps as i8) as i16) << 8) as u16 + tpf as u16,
+    };
+    writer.write_all(&division.to_be_bytes()).await?;
+    Ok(StreamingWriter { writer })
+}
+
+#[must_use]
+#[derive(Debug)]
+pub struct StreamingWriter<'w, W: AsyncWrite + Unpin> {
+    writer: &'w mut W,
+}
+
+impl<'w, W: AsyncWrite + Unpin> StreamingWriter<'w, W> {
+    pub fn track(&mut self) -> StreamingTrackWriter<'_, 'w, W> {
+        StreamingTrackWriter {
+            writer: self,
+            body: Vec::new(),
+            last_status: 0,
+            sysex_continuation: false,
+        }
+    }
+}
+
+#[must_use]
+#[derive(Debug)]
+pub struct StreamingTrackWriter<'t, 'w, W: AsyncWrite + Unpin> {
+    writer: &'t mut StreamingWriter<'w, W>,
+    body: Vec<u8>,
+    last_status: u8,
+    sysex_continuation: bool,
+}
+
+impl<'t, 'w, W: AsyncWrite + Unpin> StreamingTrackWriter<'t, 'w, W> {
+    pub async fn vlq(&mut self, v: u32) -> fio::Result<()> {
+        let mut buf = [0u8; 4];
+        let len = encode_vlq(v, &mut buf);
+        self.body.extend_from_slice(&buf[..len]);
+        Ok(())
+    }
+    pub async fn raw_event(&mut self, delta: u32, data: &[u8]) -> fio::Result<()> {
+        self.vlq(delta).await?;
+        self.body.extend_from_slice(data);
+        Ok(())
+    }
+    pub async fn midi_event(&mut self, delta: u32, data: &[u8]) -> fio::Result<()> {
+        debug_assert!(!self.sysex_continuation);
+        debug_assert!(data[0] >= 0x80 && data[0] < 0xF0);
+        debug_assert!(if data[0] >= 0xC0 && data[0] < 0xF0 {
+            data.len() == 2
+        } else {
+            data.len() == 3
+        });
+        debug_assert!(data.iter().skip(1).all(|b| *b < 0x80));
+        if data[0] == self.last_status {
+            self.raw_event(delta, &data[1..data.len()]).await?;
+        } else {
+            self.raw_event(delta, data).await?;
+            self.last_status = data[0];
+        }
+        Ok(())
+    }
+    pub async fn meta_event(&mut self, delta: u32, id: u8, data: &[u8]) -> fio::Result<()> {
+        debug_assert!(!self.sysex_continuation);
+        debug_assert!(id < 0x80);
+        self.last_status = 0;
+        self.vlq(delta).await?;
+        self.body.extend_from_slice(&[0xFFu8, id]);
+        self.vlq(data.len().try_into().unwrap()).await?;
+        self.body.extend_from_slice(data);
+        Ok(())
+    }
+    pub async fn escaped_event(&mut self, delta: u32, data: &[u8]) -> fio::Result<()> {
+        debug_assert!(!self.sysex_continuation);
+        self.last_status = 0;
+        self.vlq(delta).await?;
+        self.body.push(0xF7u8);
+        self.vlq(data.len().try_into().unwrap()).await?;
+        self.body.extend_from_slice(data);
+        Ok(())
+    }
+    pub async fn sysex_event(&mut self, delta: u32, data: &[u8]) -> fio::Result<()> {
+        let status = data[0];
+        debug_assert!(status == 0xF0 || status == 0xF7);
+        if status == 0xF0 {
+            debug_assert!(!self.sysex_continuation);
+            self.sysex_continuation = true;
+        } else if status == 0xF7 {
+            debug_assert!(self.sysex_continuation);
+        }
+        if data.len() > 1 && data.last() == Some(&0xF7) {
+            debug_assert!(data.iter().skip(1).take(data.len() - 2).all(|b| *b < 0x80));
+            self.sysex_continuation = false;
+        } else {
+            debug_assert!(data.iter().skip(1).all(|b| *b < 0x80));
+        }
+        self.last_status = 0;
+        self.vlq(delta).await?;
+        self.body.push(status);
+        self.vlq((data.len() - 1).try_into().unwrap()).await?;
+        self.body.extend_from_slice(&data[1..data.len()]);
+        Ok(())
+    }
+    pub async fn finish(self) -> fio::Result<()> {
+        let track_len = u32::try_from(self.body.len()).unwrap();
+        let writer = &mut self.writer.writer;
+        writer.write_all(b"MTrk").await?;
+        writer.write_all(&track_len.to_be_bytes()).await?;
+        writer.write_all(&self.body).await
+    }
+}